@@ -22,40 +22,88 @@ fn func_return() -> Vec<u8> {
     arm_u32_insn_to_bytes(0xD65F0000 | (30 << 5))
 }
 
-fn incr() -> Vec<u8> {
-    // ADDS x0, x0, 0x01
-    // -:-:-:-:-:-:-:-:-:-:imm12::::::::::::Rn:::::Rd::::
-    arm_u32_insn_to_bytes(0xB1000000 | (0x01 << 10))
+// Loads a 64-bit immediate into register `rd` with a MOVZ followed by as many MOVK as needed,
+// each carrying one 16-bit chunk (LSL 0/16/32/48). Used as the scratch-register fallback when an
+// immediate doesn't fit the 12-bit field of ADD/SUB (immediate).
+fn movz_movk_sequence(rd: u32, value: i64) -> Vec<u8> {
+    let bits = value as u64;
+    let chunks = [
+        (bits & 0xffff) as u32,
+        ((bits >> 16) & 0xffff) as u32,
+        ((bits >> 32) & 0xffff) as u32,
+        ((bits >> 48) & 0xffff) as u32,
+    ];
+
+    let mut insns = Vec::new();
+    let mut emitted_movz = false;
+    for (hw, chunk) in chunks.iter().enumerate() {
+        if *chunk == 0 && hw != 0 {
+            continue;
+        }
+        if !emitted_movz {
+            // MOVZ Xd, imm16, LSL (hw * 16)
+            // sf:10:100101:hw(2):imm16::::::::::::::::Rd::::
+            insns.extend(arm_u32_insn_to_bytes(
+                0xD2800000 | ((hw as u32) << 21) | (chunk << 5) | rd,
+            ));
+            emitted_movz = true;
+        } else {
+            // MOVK Xd, imm16, LSL (hw * 16)
+            // sf:11:100101:hw(2):imm16::::::::::::::::Rd::::
+            insns.extend(arm_u32_insn_to_bytes(
+                0xF2800000 | ((hw as u32) << 21) | (chunk << 5) | rd,
+            ));
+        }
+    }
+    insns
 }
 
-fn decr() -> Vec<u8> {
-    // SUBS x0, x0, 0x01
-    // -:-:-:-:-:-:-:-:-:-:imm12::::::::::::Rn:::::Rd::::
-    arm_u32_insn_to_bytes(0xF1000000 | (0x01 << 10))
+// ADD/SUB (immediate), Xd = Xn = x0: sf:op:S:100010:sh:imm12:Rn:Rd. `opcode_base` selects
+// ADDS (0xB1000000) or SUBS (0xF1000000); `sh` is set when the 12-bit field holds the value
+// shifted right by 12 (i.e. the immediate is a multiple of 4096 up to 4095 << 12). Immediates
+// that fit neither form fall back to loading a scratch register (x1) and an ADD/SUB (register).
+fn add_or_sub_imm(opcode_base: u32, reg_opcode_base: u32, value: i64) -> Vec<u8> {
+    if let Ok(imm12) = u32::try_from(value) {
+        if imm12 <= 0xfff {
+            return arm_u32_insn_to_bytes(opcode_base | (imm12 << 10));
+        }
+        if imm12 % 4096 == 0 && imm12 / 4096 <= 0xfff {
+            return arm_u32_insn_to_bytes(opcode_base | (1 << 22) | ((imm12 / 4096) << 10));
+        }
+    }
+
+    // ADD/SUB (register), Xd = Xn = x0, Xm = x1: sf:op:S:01011:shift(2):0:Rm:imm6:Rn:Rd
+    let mut insns = movz_movk_sequence(0x01, value);
+    insns.extend(arm_u32_insn_to_bytes(reg_opcode_base | (0x01 << 16)));
+    insns
 }
 
-fn double() -> Vec<u8> {
-    // MOVZ x1, 0x0002, LSL 0
-    // -:-:-:-:-:-:-:-:-:-:-:imm16::::::::::::::::Rd::::
-    let mut insns = arm_u32_insn_to_bytes(0x52800000 | (0x02 << 5) | 0x01);
+fn incr(imm: i64) -> Vec<u8> {
+    // ADDS x0, x0, #imm (or ADDS x0, x0, x1 via the scratch-register fallback)
+    add_or_sub_imm(0xB1000000, 0xAB000000, imm)
+}
 
-    // MOVZ x2, 0x0000, LSL 0
-    // -:-:-:-:-:-:-:-:-:-:-:imm16::::::::::::::::Rd::::
-    insns.extend(arm_u32_insn_to_bytes(0x52800000 | 0x02));
+fn decr(imm: i64) -> Vec<u8> {
+    // SUBS x0, x0, #imm (or SUBS x0, x0, x1 via the scratch-register fallback)
+    add_or_sub_imm(0xF1000000, 0xEB000000, imm)
+}
+
+fn double(imm: i64) -> Vec<u8> {
+    // MOVZ/MOVK x1, imm
+    let mut insns = movz_movk_sequence(0x01, imm);
 
-    // MADD x0, x0, x1, x2
+    // MADD x0, x0, x1, xzr (xzr = 0b11111 = 0x1F)
     // -:-:-:-:-:-:-:-:-:-:-:Rm:::::-:Ra:::::Rn:::::Rd::::
     insns.extend(arm_u32_insn_to_bytes(
-        0x9B000000 | (0x01 << 16) | (0x02 << 10),
+        0x9B000000 | (0x01 << 16) | (0x1F << 10),
     ));
 
     insns
 }
 
-fn halve() -> Vec<u8> {
-    // MOVZ x1, 0x0002, LSL 0
-    // -:-:-:-:-:-:-:-:-:-:-:imm16::::::::::::::::Rd::::
-    let mut insns = arm_u32_insn_to_bytes(0x52800000 | (0x02 << 5) | 0x01);
+fn halve(imm: i64) -> Vec<u8> {
+    // MOVZ/MOVK x1, imm
+    let mut insns = movz_movk_sequence(0x01, imm);
 
     // SDIV x0, x0, x1
     // -:-:-:-:-:-:-:-:-:-:-:Rm:::::-:-:-:-:-:-:Rn:::::Rd::::
@@ -64,13 +112,123 @@ fn halve() -> Vec<u8> {
     insns
 }
 
+// CBZ x0, #0: sf:011010:0:imm19:Rt, Rt = x0. The imm19 field is left zeroed; returns the bytes
+// and the offset of the whole instruction word, which `patch_branch` rewrites once the target
+// is known.
+pub fn branch_if_zero() -> (Vec<u8>, usize) {
+    (arm_u32_insn_to_bytes(0xB4000000), 0)
+}
+
+// B #0: 000101:imm26. Like `branch_if_zero`, the offset returned is the start of the word.
+pub fn jump() -> (Vec<u8>, usize) {
+    (arm_u32_insn_to_bytes(0x14000000), 0)
+}
+
+// Patches the imm19 (CBZ) or imm26 (B) field of the placeholder word at `site` so it branches to
+// `target`. Both fields hold `(target - site) >> 2`, since branch targets must be 4-byte aligned
+// and the encoded offset counts instructions, not bytes; the top bits of the placeholder word
+// (fixed at encode time, since imm = 0) identify which of the two forms it is.
+pub fn patch_branch(code: &mut [u8], site: usize, target: usize) {
+    let byte_disp = target as i64 - site as i64;
+    assert_eq!(byte_disp % 4, 0, "aarch64 branch target must be 4-byte aligned");
+    let word_disp = byte_disp / 4;
+
+    let word = u32::from_le_bytes(code[site..site + 4].try_into().unwrap());
+    let patched = if (word & 0xFC000000) == 0x14000000 {
+        // B, imm26 at bits 25..0
+        assert!(
+            (-(1 << 25)..(1 << 25)).contains(&word_disp),
+            "branch offset doesn't fit imm26 (widening into a veneer/chain of branches isn't implemented)"
+        );
+        word | ((word_disp as u32) & 0x03FF_FFFF)
+    } else {
+        // CBZ, imm19 at bits 23..5
+        assert!(
+            (-(1 << 18)..(1 << 18)).contains(&word_disp),
+            "branch offset doesn't fit imm19 (widening into a veneer/chain of branches isn't implemented)"
+        );
+        word | (((word_disp as u32) & 0x0007_FFFF) << 5)
+    };
+
+    code[site..site + 4].copy_from_slice(&patched.to_le_bytes());
+}
+
 pub fn native_insns(insn: &Insn) -> Vec<u8> {
     match insn {
         Insn::Reset => reset_accum(),
         Insn::Return => func_return(),
-        Insn::Incr => incr(),
-        Insn::Decr => decr(),
-        Insn::Double => double(),
-        Insn::Halve => halve(),
+        Insn::Incr(imm) => incr(*imm),
+        Insn::Decr(imm) => decr(*imm),
+        Insn::Double(imm) => double(*imm),
+        Insn::Halve(imm) => halve(*imm),
+        Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_) => {
+            unreachable!("control-flow instructions are lowered directly by jit(), not native_insns")
+        }
+    }
+}
+
+// Float accumulator mode: same six `Insn`s, but the accumulator lives in D0 as an `f64`. X1/D1
+// are scratch, same roles X1 plays in the integer backend above. FMOV/SCVTF/FADD/FSUB/FMUL/FDIV
+// opcodes below are derived by hand from the ARMv8 encoding tables the same way the integer
+// helpers above are; see their comments for the bitfield layout this follows.
+
+fn reset_accum_f64() -> Vec<u8> {
+    // FMOV D0, XZR: moving the all-zero register's bit pattern into D0 gives exactly 0.0, since
+    // the immediate form of FMOV can't encode 0.0 directly.
+    // sf:0:S:11110:type(01):1:rmode(00):111:000000:Rn:Rd, Rn = XZR (31), Rd = D0 (0)
+    arm_u32_insn_to_bytes(0x9E670000 | (31 << 5))
+}
+
+fn func_return_f64() -> Vec<u8> {
+    func_return()
+}
+
+// SCVTF D1, X1: converts the signed 64-bit integer in X1 into the double in D1.
+// sf:0:S:11110:type(01):1:rmode(00):010:000000:Rn:Rd, Rn = Rd = 1
+fn scvtf_d1_from_x1() -> Vec<u8> {
+    arm_u32_insn_to_bytes(0x9E620000 | (0x01 << 5) | 0x01)
+}
+
+// Floating-point (scalar) arithmetic, Dd = Dn = D0, Dm = D1:
+// 0:0:S:11110:type(01):1:Rm:opcode(4):10:Rn:Rd. `opcode_base` picks FADD/FSUB/FMUL/FDIV; Rm is
+// fixed at D1 (1 << 16).
+fn load_imm_as_f64_then(opcode_base: u32, imm: i64) -> Vec<u8> {
+    let mut insns = movz_movk_sequence(0x01, imm);
+    insns.extend(scvtf_d1_from_x1());
+    insns.extend(arm_u32_insn_to_bytes(opcode_base | (0x01 << 16)));
+    insns
+}
+
+fn incr_f64(imm: i64) -> Vec<u8> {
+    // FADD D0, D0, D1
+    load_imm_as_f64_then(0x1E602800, imm)
+}
+
+fn decr_f64(imm: i64) -> Vec<u8> {
+    // FSUB D0, D0, D1
+    load_imm_as_f64_then(0x1E603800, imm)
+}
+
+fn double_f64(imm: i64) -> Vec<u8> {
+    // FMUL D0, D0, D1
+    load_imm_as_f64_then(0x1E600800, imm)
+}
+
+fn halve_f64(imm: i64) -> Vec<u8> {
+    // FDIV D0, D0, D1
+    load_imm_as_f64_then(0x1E601800, imm)
+}
+
+pub fn native_insns_f64(insn: &Insn) -> Vec<u8> {
+    match insn {
+        Insn::Reset => reset_accum_f64(),
+        Insn::Return => func_return_f64(),
+        Insn::Incr(imm) => incr_f64(*imm),
+        Insn::Decr(imm) => decr_f64(*imm),
+        Insn::Double(imm) => double_f64(*imm),
+        Insn::Halve(imm) => halve_f64(*imm),
+        Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_) => {
+            unreachable!("control-flow instructions are not supported in float mode")
+        }
     }
 }
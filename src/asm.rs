@@ -0,0 +1,513 @@
+// A small typed assembler for the x86_64 backend. Instead of hand-building REX/ModRM/SIB bytes
+// inline at every call site, callers describe operands with `Reg64`/`MemOp` and ask an
+// `Assembler` to emit the instruction; the encoder is responsible for picking the right prefix,
+// ModRM mode, and displacement size.
+
+// Format of the REX prefix byte, from https://pyokagan.name/blog/2019-09-20-x86encoding/
+// 0100	4 bits	Fixed bit pattern
+// W	1 bit	When 1, a 64-bit operand size is used. Otherwise, when 0, the default operand size is used (which is 32-bit for most but not all instructions)
+// R	1 bit	This 1-bit value is an extension to the MODRM.reg field.
+// X	1 bit	This 1-bit value is an extension to the SIB.index field.
+// B	1 bit	This 1-bit value is an extension to the MODRM.rm field or the SIB.base field.
+fn rex(w: bool, r: bool, x: bool, b: bool) -> u8 {
+    0b01000000 | ((w as u8) << 3) | ((r as u8) << 2) | ((x as u8) << 1) | (b as u8)
+}
+
+// From https://cs.wellesley.edu/~cs342/fall12/papers/isa.pdf
+// A slash followed by a digit, such as /2, indicates that one of the operands to the instruction
+// is a memory address or register (denoted mem or r/m, with an optional size). This is to be
+// encoded as an effective address, with a ModR/M byte, an optional SIB byte, and an optional
+// displacement, and the spare (register) field of the ModR/M byte should be the digit given
+// (which will be from 0 to 7, so it fits in three bits).
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    ((mode & 0b11) << 6) | ((reg & 0b111) << 3) | (rm & 0b111)
+}
+
+fn sib(base: u8) -> u8 {
+    // scale=00, index=100 (none), base as given
+    0b00100000 | (base & 0b111)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum Reg64 {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg64 {
+    fn index(self) -> u8 {
+        match self {
+            Reg64::Rax => 0,
+            Reg64::Rcx => 1,
+            Reg64::Rdx => 2,
+            Reg64::Rbx => 3,
+            Reg64::Rsp => 4,
+            Reg64::Rbp => 5,
+            Reg64::Rsi => 6,
+            Reg64::Rdi => 7,
+            Reg64::R8 => 8,
+            Reg64::R9 => 9,
+            Reg64::R10 => 10,
+            Reg64::R11 => 11,
+            Reg64::R12 => 12,
+            Reg64::R13 => 13,
+            Reg64::R14 => 14,
+            Reg64::R15 => 15,
+        }
+    }
+
+    fn low3(self) -> u8 {
+        self.index() & 0b111
+    }
+
+    fn is_extended(self) -> bool {
+        self.index() >= 8
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum Reg32 {
+    Eax,
+    Ecx,
+    Edx,
+    Ebx,
+    Esp,
+    Ebp,
+    Esi,
+    Edi,
+}
+
+#[allow(dead_code)]
+impl Reg32 {
+    fn low3(self) -> u8 {
+        match self {
+            Reg32::Eax => 0,
+            Reg32::Ecx => 1,
+            Reg32::Edx => 2,
+            Reg32::Ebx => 3,
+            Reg32::Esp => 4,
+            Reg32::Ebp => 5,
+            Reg32::Esi => 6,
+            Reg32::Edi => 7,
+        }
+    }
+}
+
+// An `r/m` memory operand addressed through a single base register, optionally with a
+// displacement. There is no scaled-index support; that's more than this JIT needs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum MemOp {
+    Indirect(Reg64),
+    IndirectDisp(Reg64, i32),
+}
+
+impl MemOp {
+    fn base(self) -> Reg64 {
+        match self {
+            MemOp::Indirect(base) => base,
+            MemOp::IndirectDisp(base, _) => base,
+        }
+    }
+
+    fn disp(self) -> i32 {
+        match self {
+            MemOp::Indirect(_) => 0,
+            MemOp::IndirectDisp(_, disp) => disp,
+        }
+    }
+}
+
+// An XMM register, used by the SSE2 scalar double-precision instructions for the float
+// accumulator mode. Only the two registers this JIT actually needs are named; like `Reg32`,
+// there's no extended-register (R8-R15) support since nothing here needs it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum Xmm {
+    Xmm0,
+    Xmm1,
+}
+
+impl Xmm {
+    fn low3(self) -> u8 {
+        match self {
+            Xmm::Xmm0 => 0,
+            Xmm::Xmm1 => 1,
+        }
+    }
+}
+
+// An r/m64 operand: either a bare register, or a memory location.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum Rm64 {
+    Reg(Reg64),
+    Mem(MemOp),
+}
+
+impl From<Reg64> for Rm64 {
+    fn from(reg: Reg64) -> Self {
+        Rm64::Reg(reg)
+    }
+}
+
+impl From<MemOp> for Rm64 {
+    fn from(mem: MemOp) -> Self {
+        Rm64::Mem(mem)
+    }
+}
+
+// Appends the ModRM byte (and SIB/displacement bytes where the operand is memory) for `rm`
+// against the given `reg` field, returning whether the base/rm register needs REX.B.
+//
+// Base register encoding 101 (RBP/R13) with mod=00 means RIP-relative in 64-bit mode, so a
+// bare `[rbp]`/`[r13]` must instead be encoded as mod=01 with an explicit disp8 of 0. Base
+// register encoding 100 (RSP/R12) always requires a SIB byte, since mod+rm=100 is the SIB
+// escape rather than a direct `[rsp]`/`[r12]` addressing form.
+fn encode_modrm(bytes: &mut Vec<u8>, reg: u8, rm: Rm64) -> bool {
+    match rm {
+        Rm64::Reg(r) => {
+            bytes.push(modrm(0b11, reg, r.low3()));
+            r.is_extended()
+        }
+        Rm64::Mem(mem) => {
+            let base = mem.base();
+            let disp = mem.disp();
+            let needs_sib = base.low3() == 0b100;
+            let force_disp8 = base.low3() == 0b101 && disp == 0;
+
+            let mode = if disp == 0 && !force_disp8 {
+                0b00
+            } else if i8::try_from(disp).is_ok() {
+                0b01
+            } else {
+                0b10
+            };
+
+            bytes.push(modrm(mode, reg, if needs_sib { 0b100 } else { base.low3() }));
+            if needs_sib {
+                bytes.push(sib(base.low3()));
+            }
+            match mode {
+                0b00 => {}
+                0b01 => bytes.push(disp as i8 as u8),
+                0b10 => bytes.extend(disp.to_le_bytes()),
+                _ => unreachable!(),
+            }
+
+            base.is_extended()
+        }
+    }
+}
+
+// Accumulates emitted bytes for a sequence of instructions operating on 64-bit operands.
+pub struct Assembler {
+    bytes: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler { bytes: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn emit_imm_group(&mut self, digit: u8, dst: Rm64, imm: i64) {
+        // ADD/SUB/etc r/m64, imm -> REX.W + 83 /digit ib when imm fits in a sign-extended imm8,
+        // else REX.W + 81 /digit id with a full imm32.
+        if let Ok(imm8) = i8::try_from(imm) {
+            let rex_byte_placeholder = self.bytes.len();
+            self.bytes.push(0); // REX placeholder, patched below
+            self.bytes.push(0x83);
+            let b = encode_modrm(&mut self.bytes, digit, dst);
+            self.bytes.push(imm8 as u8);
+            self.bytes[rex_byte_placeholder] = rex(true, false, false, b);
+        } else {
+            let imm32 = i32::try_from(imm).expect("immediate does not fit in 32 bits");
+            let rex_byte_placeholder = self.bytes.len();
+            self.bytes.push(0);
+            self.bytes.push(0x81);
+            let b = encode_modrm(&mut self.bytes, digit, dst);
+            self.bytes.extend(imm32.to_le_bytes());
+            self.bytes[rex_byte_placeholder] = rex(true, false, false, b);
+        }
+    }
+
+    /// ADD r/m64, imm
+    pub fn add(&mut self, dst: Rm64, imm: i64) -> &mut Self {
+        self.emit_imm_group(0x00, dst, imm);
+        self
+    }
+
+    /// SUB r/m64, imm
+    pub fn sub(&mut self, dst: Rm64, imm: i64) -> &mut Self {
+        self.emit_imm_group(0x05, dst, imm);
+        self
+    }
+
+    /// XOR r/m64, r64
+    pub fn xor(&mut self, dst: Rm64, src: Reg64) -> &mut Self {
+        let rex_byte_placeholder = self.bytes.len();
+        self.bytes.push(0);
+        self.bytes.push(0x31);
+        let b = encode_modrm(&mut self.bytes, src.low3(), dst);
+        self.bytes[rex_byte_placeholder] = rex(true, src.is_extended(), false, b);
+        self
+    }
+
+    /// MOV r/m64, r64
+    #[allow(dead_code)]
+    pub fn mov_store(&mut self, dst: Rm64, src: Reg64) -> &mut Self {
+        let rex_byte_placeholder = self.bytes.len();
+        self.bytes.push(0);
+        self.bytes.push(0x89);
+        let b = encode_modrm(&mut self.bytes, src.low3(), dst);
+        self.bytes[rex_byte_placeholder] = rex(true, src.is_extended(), false, b);
+        self
+    }
+
+    /// MOV r64, r/m64
+    #[allow(dead_code)]
+    pub fn mov_load(&mut self, dst: Reg64, src: Rm64) -> &mut Self {
+        let rex_byte_placeholder = self.bytes.len();
+        self.bytes.push(0);
+        self.bytes.push(0x8B);
+        let b = encode_modrm(&mut self.bytes, dst.low3(), src);
+        self.bytes[rex_byte_placeholder] = rex(true, dst.is_extended(), false, b);
+        self
+    }
+
+    /// MOV r64, imm -> REX.W + C7 /0 id (imm32, sign-extended) when `imm` fits in 32 bits,
+    /// else REX.W + B8+rd io (full imm64).
+    pub fn mov_imm(&mut self, dst: Reg64, imm: i64) -> &mut Self {
+        if let Ok(imm32) = i32::try_from(imm) {
+            self.bytes.push(rex(true, false, false, dst.is_extended()));
+            self.bytes.push(0xC7);
+            self.bytes.push(modrm(0b11, 0x00, dst.low3()));
+            self.bytes.extend(imm32.to_le_bytes());
+        } else {
+            self.bytes.push(rex(true, false, false, dst.is_extended()));
+            self.bytes.push(0xB8 + dst.low3());
+            self.bytes.extend(imm.to_le_bytes());
+        }
+        self
+    }
+
+    /// IMUL r/m64 -> REX.W + F7 /5. RDX:RAX <- RAX * r/m64.
+    pub fn imul(&mut self, src: Rm64) -> &mut Self {
+        let rex_byte_placeholder = self.bytes.len();
+        self.bytes.push(0);
+        self.bytes.push(0xF7);
+        let b = encode_modrm(&mut self.bytes, 0x05, src);
+        self.bytes[rex_byte_placeholder] = rex(true, false, false, b);
+        self
+    }
+
+    /// CQO -> REX.W + 99. Sign-extends RAX into RDX:RAX, required ahead of IDIV so a negative
+    /// RAX divides correctly instead of dividing by whatever garbage RDX already holds.
+    pub fn cqo(&mut self) -> &mut Self {
+        self.bytes.push(rex(true, false, false, false));
+        self.bytes.push(0x99);
+        self
+    }
+
+    /// IDIV r/m64 -> REX.W + F7 /7. RDX:RAX / r/m64 -> RAX = quotient, RDX = remainder.
+    pub fn idiv(&mut self, src: Rm64) -> &mut Self {
+        let rex_byte_placeholder = self.bytes.len();
+        self.bytes.push(0);
+        self.bytes.push(0xF7);
+        let b = encode_modrm(&mut self.bytes, 0x07, src);
+        self.bytes[rex_byte_placeholder] = rex(true, false, false, b);
+        self
+    }
+
+    /// RET
+    pub fn ret(&mut self) -> &mut Self {
+        self.bytes.push(0xC3);
+        self
+    }
+
+    /// TEST r/m64, r64, with both operands the same register -> REX.W + 85 /r. Used ahead of
+    /// `jz`/`jnz` to set the zero flag from a register's value without a separate CMP.
+    pub fn test_self(&mut self, r: Reg64) -> &mut Self {
+        self.bytes.push(rex(true, r.is_extended(), false, r.is_extended()));
+        self.bytes.push(0x85);
+        self.bytes.push(modrm(0b11, r.low3(), r.low3()));
+        self
+    }
+
+    /// JZ rel32 -> 0F 84 id. The displacement is left zeroed; returns the byte offset of the
+    /// 4-byte field so the caller can patch it once the branch target is known.
+    pub fn jz(&mut self) -> usize {
+        self.bytes.push(0x0F);
+        self.bytes.push(0x84);
+        let site = self.bytes.len();
+        self.bytes.extend([0u8; 4]);
+        site
+    }
+
+    /// JMP rel32 -> E9 id. Like `jz`, returns the offset of the displacement field to patch.
+    pub fn jmp(&mut self) -> usize {
+        self.bytes.push(0xE9);
+        let site = self.bytes.len();
+        self.bytes.extend([0u8; 4]);
+        site
+    }
+
+    /// XORPD xmm, xmm -> 66 0F 57 /r. Used to zero the float accumulator, the SSE2 analogue
+    /// of `xor` on the integer accumulator.
+    pub fn xorpd(&mut self, dst: Xmm, src: Xmm) -> &mut Self {
+        self.bytes.push(0x66);
+        self.bytes.push(0x0F);
+        self.bytes.push(0x57);
+        self.bytes.push(modrm(0b11, dst.low3(), src.low3()));
+        self
+    }
+
+    /// CVTSI2SD xmm, r64 -> F2 REX.W 0F 2A /r. Converts a signed 64-bit integer immediate
+    /// (already loaded into a GPR) into a double, so it can feed the SSE2 arithmetic below.
+    pub fn cvtsi2sd(&mut self, dst: Xmm, src: Reg64) -> &mut Self {
+        self.bytes.push(0xF2);
+        self.bytes.push(rex(true, false, false, src.is_extended()));
+        self.bytes.push(0x0F);
+        self.bytes.push(0x2A);
+        self.bytes.push(modrm(0b11, dst.low3(), src.low3()));
+        self
+    }
+
+    fn sse2_arith(&mut self, opcode: u8, dst: Xmm, src: Xmm) -> &mut Self {
+        self.bytes.push(0xF2);
+        self.bytes.push(0x0F);
+        self.bytes.push(opcode);
+        self.bytes.push(modrm(0b11, dst.low3(), src.low3()));
+        self
+    }
+
+    /// ADDSD xmm, xmm -> F2 0F 58 /r
+    pub fn addsd(&mut self, dst: Xmm, src: Xmm) -> &mut Self {
+        self.sse2_arith(0x58, dst, src)
+    }
+
+    /// SUBSD xmm, xmm -> F2 0F 5C /r
+    pub fn subsd(&mut self, dst: Xmm, src: Xmm) -> &mut Self {
+        self.sse2_arith(0x5C, dst, src)
+    }
+
+    /// MULSD xmm, xmm -> F2 0F 59 /r
+    pub fn mulsd(&mut self, dst: Xmm, src: Xmm) -> &mut Self {
+        self.sse2_arith(0x59, dst, src)
+    }
+
+    /// DIVSD xmm, xmm -> F2 0F 5E /r
+    pub fn divsd(&mut self, dst: Xmm, src: Xmm) -> &mut Self {
+        self.sse2_arith(0x5E, dst, src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Assembler, MemOp, Reg64};
+
+    #[test]
+    fn test_add_reg_imm8() {
+        // REX.W 83 /0 ib
+        let mut asm = Assembler::new();
+        asm.add(Reg64::Rax.into(), 5);
+        assert_eq!(vec![0x48, 0x83, 0xC0, 0x05], asm.finish());
+    }
+
+    #[test]
+    fn test_add_reg_imm32() {
+        // imm doesn't fit in imm8, so this falls back to REX.W 81 /0 id
+        let mut asm = Assembler::new();
+        asm.add(Reg64::Rax.into(), 1000);
+        assert_eq!(
+            vec![0x48, 0x81, 0xC0, 0xE8, 0x03, 0x00, 0x00],
+            asm.finish()
+        );
+    }
+
+    #[test]
+    fn test_xor_extended_reg() {
+        // R8 needs REX.B on the r/m operand
+        let mut asm = Assembler::new();
+        asm.xor(Reg64::R8.into(), Reg64::R8);
+        assert_eq!(vec![0x4D, 0x31, 0xC0], asm.finish());
+    }
+
+    #[test]
+    fn test_mov_load_mem_indirect() {
+        // [rax], no displacement -> mod=00
+        let mut asm = Assembler::new();
+        asm.mov_load(Reg64::Rcx, MemOp::Indirect(Reg64::Rax).into());
+        assert_eq!(vec![0x48, 0x8B, 0x08], asm.finish());
+    }
+
+    #[test]
+    fn test_mov_load_mem_disp8() {
+        // [rcx+8], disp fits in a byte -> mod=01
+        let mut asm = Assembler::new();
+        asm.mov_load(Reg64::Rax, MemOp::IndirectDisp(Reg64::Rcx, 8).into());
+        assert_eq!(vec![0x48, 0x8B, 0x41, 0x08], asm.finish());
+    }
+
+    #[test]
+    fn test_mov_load_mem_disp32() {
+        // disp doesn't fit in a byte -> mod=10, full disp32
+        let mut asm = Assembler::new();
+        asm.mov_load(Reg64::Rax, MemOp::IndirectDisp(Reg64::Rcx, 1000).into());
+        assert_eq!(
+            vec![0x48, 0x8B, 0x81, 0xE8, 0x03, 0x00, 0x00],
+            asm.finish()
+        );
+    }
+
+    #[test]
+    fn test_mov_load_mem_rsp_base_needs_sib() {
+        // RSP as a base always needs a SIB byte, since mod+rm=100 is the SIB escape.
+        let mut asm = Assembler::new();
+        asm.mov_load(Reg64::Rax, MemOp::Indirect(Reg64::Rsp).into());
+        assert_eq!(vec![0x48, 0x8B, 0x04, 0x24], asm.finish());
+    }
+
+    #[test]
+    fn test_mov_load_mem_rbp_base_forces_disp8() {
+        // Bare [rbp] would collide with the RIP-relative encoding, so mod=00 is disallowed for
+        // this base; a zero disp8 is forced instead.
+        let mut asm = Assembler::new();
+        asm.mov_load(Reg64::Rax, MemOp::Indirect(Reg64::Rbp).into());
+        assert_eq!(vec![0x48, 0x8B, 0x45, 0x00], asm.finish());
+    }
+
+    #[test]
+    fn test_cqo() {
+        let mut asm = Assembler::new();
+        asm.cqo();
+        assert_eq!(vec![0x48, 0x99], asm.finish());
+    }
+
+    #[test]
+    fn test_ret() {
+        let mut asm = Assembler::new();
+        asm.ret();
+        assert_eq!(vec![0xC3], asm.finish());
+    }
+}
@@ -0,0 +1,54 @@
+// Pretty-prints JITed machine code by decoding it back into mnemonics, so a `--show-asm` run can
+// confirm the REX/ModRM (or AArch64/RISC-V word) encoding actually matches what was intended.
+// Uses `capstone`, which covers every host architecture this crate targets, so `disassemble`
+// doesn't need its own per-arch decoder.
+
+use capstone::prelude::*;
+
+fn capstone_for_host() -> Capstone {
+    #[cfg(target_arch = "x86_64")]
+    {
+        Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .build()
+            .expect("failed to initialize capstone for x86_64")
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        Capstone::new()
+            .arm64()
+            .mode(arch::arm64::ArchMode::Arm)
+            .build()
+            .expect("failed to initialize capstone for aarch64")
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    {
+        Capstone::new()
+            .riscv()
+            .mode(arch::riscv::ArchMode::RiscV64)
+            .build()
+            .expect("failed to initialize capstone for riscv64")
+    }
+}
+
+/// Decodes `code` (machine code for the host architecture) into one `"hex bytes  mnemonic
+/// operands"` line per instruction, e.g. `"4831c0  xor rax, rax"`.
+pub fn disassemble(code: &[u8]) -> Vec<String> {
+    let cs = capstone_for_host();
+    let insns = cs.disasm_all(code, 0x0).expect("failed to disassemble emitted code");
+
+    insns
+        .iter()
+        .map(|insn| {
+            let hex: String = insn.bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            let mnemonic = insn.mnemonic().unwrap_or("");
+            match insn.op_str() {
+                Some(ops) if !ops.is_empty() => format!("{}  {} {}", hex, mnemonic, ops),
+                _ => format!("{}  {}", hex, mnemonic),
+            }
+        })
+        .collect()
+}
@@ -1,10 +1,15 @@
 // A simple integer calculator:
-// `+` or `-` means add or subtract by 1
-// `*` or `/` means multiply or divide by 2
+// `+` or `-` means add or subtract by 1 (or by the digits that follow, e.g. `+5`)
+// `*` or `/` means multiply or divide by 2 (or by the digits that follow, e.g. `*3`)
+// With `--float`, the accumulator is an `f64` instead and `/` is true division.
 
+use std::collections::HashMap;
 use std::env::args;
 use std::iter::Iterator;
 
+#[cfg(target_arch = "x86_64")]
+mod asm;
+
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
@@ -17,10 +22,42 @@ mod aarch64;
 #[cfg(target_arch = "aarch64")]
 use aarch64 as native_insns;
 
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+
+#[cfg(target_arch = "riscv64")]
+use riscv64 as native_insns;
+
+#[cfg(feature = "wasm")]
+mod wasm;
+
+#[cfg(feature = "disasm")]
+mod disasm;
+
 fn main() {
     // let mut accumulator = 0;
     let mut program_tokens: Vec<String> = vec![];
+    #[cfg(feature = "wasm")]
+    let mut emit_wasm_path: Option<String> = None;
+    #[cfg(feature = "disasm")]
+    let mut show_asm = std::env::var_os("JITCALC_SHOW_ASM").is_some();
+    let mut float_mode = false;
+
     for arg in args().skip(1) {
+        #[cfg(feature = "wasm")]
+        if let Some(path) = arg.strip_prefix("--emit-wasm=") {
+            emit_wasm_path = Some(path.to_string());
+            continue;
+        }
+        #[cfg(feature = "disasm")]
+        if arg == "--show-asm" {
+            show_asm = true;
+            continue;
+        }
+        if arg == "--float" {
+            float_mode = true;
+            continue;
+        }
         program_tokens.push(arg);
     }
 
@@ -30,9 +67,46 @@ fn main() {
     let insn_seq = parse(&program);
     println!("{:?}", insn_seq);
 
+    #[cfg(feature = "wasm")]
+    if let Some(path) = emit_wasm_path {
+        let module = wasm::emit_wasm(&insn_seq);
+        std::fs::write(&path, &module).expect("failed to write wasm module");
+        println!("Wrote {} bytes of wasm to {}", module.len(), path);
+    }
+
+    if float_mode {
+        if insn_seq.iter().any(Insn::is_control_flow) {
+            eprintln!("Error: --float does not support control flow ([ ] or ( ))");
+            return;
+        }
+
+        let native_insn_seq = jit_f64(&insn_seq);
+        println!("{:?}", native_insn_seq);
+
+        #[cfg(feature = "disasm")]
+        if show_asm {
+            for line in disasm::disassemble(&native_insn_seq) {
+                println!("{}", line);
+            }
+        }
+
+        match exec_f64(&native_insn_seq) {
+            Ok(r) => println!("Result: {}", r),
+            Err(e) => eprintln!("Error: {}", e),
+        };
+        return;
+    }
+
     let native_insn_seq = jit(&insn_seq);
     println!("{:?}", native_insn_seq);
 
+    #[cfg(feature = "disasm")]
+    if show_asm {
+        for line in disasm::disassemble(&native_insn_seq) {
+            println!("{}", line);
+        }
+    }
+
     match exec(&native_insn_seq) {
         Ok(r) => println!("Result: {}", r),
         Err(e) => eprintln!("Error: {}", e),
@@ -45,24 +119,109 @@ enum Insn {
     Reset,
     Return,
     // Used in program text
-    Incr,
-    Decr,
-    Double,
-    Halve,
+    Incr(i64),
+    Decr(i64),
+    Double(i64),
+    Halve(i64),
+    // `[ ... ]`: repeat the bracketed body while the accumulator is nonzero. The id ties a
+    // `LoopStart` to its matching `LoopEnd` so the backend knows where to branch.
+    LoopStart(usize),
+    LoopEnd(usize),
+    // `( ... )`: run the bracketed body once, only if the accumulator is nonzero.
+    IfZeroSkip(usize),
+    IfZeroEnd(usize),
+}
+
+impl Insn {
+    // `jit_f64`'s backends only emit straight-line code (see `native_insns_f64`'s
+    // `unreachable!()` for these variants), so `--float` needs to reject them up front.
+    fn is_control_flow(&self) -> bool {
+        matches!(
+            self,
+            Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_)
+        )
+    }
 }
 
+// Each operator token is optionally followed by a run of decimal digits giving the immediate
+// operand, e.g. `+5` increments by 5. When the digits are omitted the historical defaults apply:
+// 1 for `+`/`-`, 2 for `*`/`/`. `[`/`]` and `(`/`)` bracket loop and zero-test conditional bodies
+// respectively; each bracket pair gets a fresh id so the two halves of a pair can find each other.
 fn parse(program: &str) -> Vec<Insn> {
-    program
-        .chars()
-        .map(|ch| match ch {
-            '+' => Some(Insn::Incr),
-            '-' => Some(Insn::Decr),
-            '*' => Some(Insn::Double),
-            '/' => Some(Insn::Halve),
-            _ => None,
-        })
-        .filter_map(|insn_opt| insn_opt)
-        .collect()
+    let mut chars = program.chars().peekable();
+    let mut insn_seq = Vec::new();
+    let mut next_label_id = 0;
+    let mut loop_stack: Vec<usize> = Vec::new();
+    let mut if_stack: Vec<usize> = Vec::new();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '[' => {
+                let id = next_label_id;
+                next_label_id += 1;
+                loop_stack.push(id);
+                insn_seq.push(Insn::LoopStart(id));
+                continue;
+            }
+            ']' => {
+                let id = loop_stack.pop().expect("unmatched ']' in program");
+                insn_seq.push(Insn::LoopEnd(id));
+                continue;
+            }
+            '(' => {
+                let id = next_label_id;
+                next_label_id += 1;
+                if_stack.push(id);
+                insn_seq.push(Insn::IfZeroSkip(id));
+                continue;
+            }
+            ')' => {
+                let id = if_stack.pop().expect("unmatched ')' in program");
+                insn_seq.push(Insn::IfZeroEnd(id));
+                continue;
+            }
+            _ => {}
+        }
+
+        let ctor: fn(i64) -> Insn = match ch {
+            '+' => Insn::Incr,
+            '-' => Insn::Decr,
+            '*' => Insn::Double,
+            '/' => Insn::Halve,
+            _ => continue,
+        };
+        let default_imm = match ch {
+            '+' | '-' => 1,
+            _ => 2,
+        };
+
+        let mut digits = String::new();
+        while let Some(d) = chars.peek() {
+            if d.is_ascii_digit() {
+                digits.push(*d);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let imm = if digits.is_empty() {
+            default_imm
+        } else {
+            digits.parse().expect("run of ASCII digits must parse as i64")
+        };
+
+        if ch == '/' {
+            assert!(imm != 0, "division by zero: '/' with an explicit 0 divisor");
+        }
+
+        insn_seq.push(ctor(imm));
+    }
+
+    assert!(loop_stack.is_empty(), "unmatched '[' in program");
+    assert!(if_stack.is_empty(), "unmatched '(' in program");
+
+    insn_seq
 }
 
 fn exec(insn_seq: &Vec<u8>) -> Result<i64, mmap_rs::Error> {
@@ -91,62 +250,265 @@ fn exec(insn_seq: &Vec<u8>) -> Result<i64, mmap_rs::Error> {
     return Ok(result);
 }
 
-fn interpret(insn_seq: &Vec<Insn>) -> u64 {
-    let mut accum = 0;
-    for insn in insn_seq {
+// Identical to `exec`, except the JITed function returns the float accumulator (`f64`) rather
+// than the integer one, for code emitted by `jit_f64`.
+fn exec_f64(insn_seq: &Vec<u8>) -> Result<f64, mmap_rs::Error> {
+    use mmap_rs::MmapOptions;
+
+    let mut code_mem = MmapOptions::new(insn_seq.len())?.map_mut()?;
+
+    unsafe {
+        std::ptr::copy(insn_seq.as_ptr(), code_mem.as_mut_ptr(), insn_seq.len());
+    }
+
+    let code_mem1 = match code_mem.make_read_only() {
+        Ok(m) => m,
+        Err((_, e)) => return Err(e),
+    };
+
+    let code_mem2 = match code_mem1.make_exec() {
+        Ok(m) => m,
+        Err((_, e)) => return Err(e),
+    };
+
+    let code_ptr = code_mem2.as_ptr() as *const ();
+    let code_func: extern "C" fn() -> f64 = unsafe { std::mem::transmute(code_ptr) };
+    let result = (code_func)();
+
+    return Ok(result);
+}
+
+// Maps each `LoopStart`/`LoopEnd` and `IfZeroSkip`/`IfZeroEnd` pair to each other's index in
+// `insn_seq`, so `interpret` can jump between them the same way the JITed branches do.
+fn match_brackets(insn_seq: &[Insn]) -> HashMap<usize, usize> {
+    let mut matches = HashMap::new();
+    let mut loop_stack = Vec::new();
+    let mut if_stack = Vec::new();
+
+    for (i, insn) in insn_seq.iter().enumerate() {
         match insn {
+            Insn::LoopStart(_) => loop_stack.push(i),
+            Insn::LoopEnd(_) => {
+                let start = loop_stack.pop().expect("unmatched loop end");
+                matches.insert(start, i);
+                matches.insert(i, start);
+            }
+            Insn::IfZeroSkip(_) => if_stack.push(i),
+            Insn::IfZeroEnd(_) => {
+                let start = if_stack.pop().expect("unmatched conditional end");
+                matches.insert(start, i);
+                matches.insert(i, start);
+            }
+            _ => {}
+        }
+    }
+
+    matches
+}
+
+fn interpret(insn_seq: &Vec<Insn>) -> i64 {
+    let matches = match_brackets(insn_seq);
+    let mut accum = 0;
+    let mut pc = 0;
+
+    while pc < insn_seq.len() {
+        match &insn_seq[pc] {
             Insn::Reset => {
                 accum = 0;
             }
             Insn::Return => {
                 break;
             }
-            Insn::Incr => {
-                accum += 1;
+            Insn::Incr(imm) => {
+                accum += imm;
+            }
+            Insn::Decr(imm) => {
+                accum -= imm;
+            }
+            Insn::Double(imm) => {
+                accum *= imm;
             }
-            Insn::Decr => {
-                accum -= 1;
+            Insn::Halve(imm) => {
+                accum /= imm;
             }
-            Insn::Double => {
-                accum *= 2;
+            Insn::LoopStart(_) => {
+                if accum == 0 {
+                    pc = matches[&pc];
+                }
             }
-            Insn::Halve => {
-                accum /= 2;
+            Insn::LoopEnd(_) => {
+                if accum != 0 {
+                    // Jump back to the matching `LoopStart` so its zero-check runs again.
+                    pc = matches[&pc];
+                    continue;
+                }
             }
+            Insn::IfZeroSkip(_) => {
+                if accum == 0 {
+                    pc = matches[&pc];
+                }
+            }
+            Insn::IfZeroEnd(_) => {}
         }
+        pc += 1;
     }
+
     accum
 }
 
+// Lowers `insn_seq` to native machine code via `native_insns`. This is a two-pass assembler:
+// instructions are emitted into `code` in a single forward sweep, during which label positions
+// (`loop_tops`, `label_ends`) and a list of unresolved forward branches (`fixups`) are recorded;
+// once the whole sequence has been emitted and every label is known, `fixups` is walked and each
+// branch is patched with its real displacement.
 fn jit(insn_seq: &Vec<Insn>) -> Vec<u8> {
-    let mut native_insns = Vec::new();
-    native_insns.extend(native_insns::native_insns(&Insn::Reset));
+    let mut code = Vec::new();
+    let mut loop_tops: HashMap<usize, usize> = HashMap::new();
+    let mut label_ends: HashMap<usize, usize> = HashMap::new();
+    let mut fixups: Vec<(usize, usize)> = Vec::new();
+
+    code.extend(native_insns::native_insns(&Insn::Reset));
+
     for insn in insn_seq {
-        native_insns.extend(native_insns::native_insns(insn));
+        match insn {
+            Insn::LoopStart(id) => {
+                loop_tops.insert(*id, code.len());
+                let (branch, site_offset) = native_insns::branch_if_zero();
+                fixups.push((code.len() + site_offset, *id));
+                code.extend(branch);
+            }
+            Insn::LoopEnd(id) => {
+                let top = *loop_tops.get(id).expect("unmatched loop end");
+                let (branch, site_offset) = native_insns::jump();
+                let site = code.len() + site_offset;
+                code.extend(branch);
+                native_insns::patch_branch(&mut code, site, top);
+                label_ends.insert(*id, code.len());
+            }
+            Insn::IfZeroSkip(id) => {
+                let (branch, site_offset) = native_insns::branch_if_zero();
+                fixups.push((code.len() + site_offset, *id));
+                code.extend(branch);
+            }
+            Insn::IfZeroEnd(id) => {
+                label_ends.insert(*id, code.len());
+            }
+            _ => {
+                code.extend(native_insns::native_insns(insn));
+            }
+        }
     }
-    native_insns.extend(native_insns::native_insns(&Insn::Return));
-    native_insns
+
+    code.extend(native_insns::native_insns(&Insn::Return));
+
+    for (site, id) in fixups {
+        let target = *label_ends.get(&id).expect("unresolved branch target");
+        native_insns::patch_branch(&mut code, site, target);
+    }
+
+    code
+}
+
+// Lowers `insn_seq` to native machine code for the float accumulator mode, via
+// `native_insns::native_insns_f64`. Straight-line only: the control-flow `Insn` variants aren't
+// supported here (see `native_insns_f64`'s `unreachable!`), so there's no two-pass backpatching
+// to do like `jit` above.
+fn jit_f64(insn_seq: &Vec<Insn>) -> Vec<u8> {
+    let mut code = Vec::new();
+
+    code.extend(native_insns::native_insns_f64(&Insn::Reset));
+    for insn in insn_seq {
+        code.extend(native_insns::native_insns_f64(insn));
+    }
+    code.extend(native_insns::native_insns_f64(&Insn::Return));
+
+    code
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{exec, interpret, jit, parse, Insn};
+    use super::{exec, exec_f64, interpret, jit, jit_f64, parse, Insn};
 
     #[test]
     fn test_canonical() {
         let program = "+ + * - /";
         let instructions = parse(program);
         assert_eq!(5, instructions.len());
-        assert_eq!(Insn::Incr, instructions[0]);
-        assert_eq!(Insn::Incr, instructions[1]);
-        assert_eq!(Insn::Double, instructions[2]);
-        assert_eq!(Insn::Decr, instructions[3]);
-        assert_eq!(Insn::Halve, instructions[4]);
+        assert_eq!(Insn::Incr(1), instructions[0]);
+        assert_eq!(Insn::Incr(1), instructions[1]);
+        assert_eq!(Insn::Double(2), instructions[2]);
+        assert_eq!(Insn::Decr(1), instructions[3]);
+        assert_eq!(Insn::Halve(2), instructions[4]);
 
         let int_result = interpret(&instructions);
         assert_eq!(1, int_result);
     }
 
+    #[test]
+    fn test_parse_immediates() {
+        let program = "+5 -12 *3 /4";
+        let instructions = parse(program);
+        assert_eq!(4, instructions.len());
+        assert_eq!(Insn::Incr(5), instructions[0]);
+        assert_eq!(Insn::Decr(12), instructions[1]);
+        assert_eq!(Insn::Double(3), instructions[2]);
+        assert_eq!(Insn::Halve(4), instructions[3]);
+    }
+
+    #[test]
+    fn test_parse_loop_and_conditional() {
+        let program = "+5[-](+10)";
+        let instructions = parse(program);
+        assert_eq!(
+            vec![
+                Insn::Incr(5),
+                Insn::LoopStart(0),
+                Insn::Decr(1),
+                Insn::LoopEnd(0),
+                Insn::IfZeroSkip(1),
+                Insn::Incr(10),
+                Insn::IfZeroEnd(1),
+            ],
+            instructions
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unmatched ']'")]
+    fn test_parse_unmatched_loop_end_panics() {
+        parse("]");
+    }
+
+    #[test]
+    #[should_panic(expected = "division by zero")]
+    fn test_parse_explicit_zero_divisor_panics() {
+        parse("+5/0");
+    }
+
+    #[test]
+    fn test_interpret_loop_drains_to_zero() {
+        let instructions = parse("+5[-]");
+        assert_eq!(0, interpret(&instructions));
+    }
+
+    #[test]
+    fn test_interpret_loop_skipped_when_already_zero() {
+        let instructions = parse("[+100]");
+        assert_eq!(0, interpret(&instructions));
+    }
+
+    #[test]
+    fn test_interpret_conditional_runs_when_nonzero() {
+        let instructions = parse("+3(+10)");
+        assert_eq!(13, interpret(&instructions));
+    }
+
+    #[test]
+    fn test_interpret_conditional_skipped_when_zero() {
+        let instructions = parse("(+10)");
+        assert_eq!(0, interpret(&instructions));
+    }
+
     #[test]
     fn test_exec_empty() {
         let result = exec(&jit(&Vec::new())).expect("mmap failure.");
@@ -155,43 +517,110 @@ mod tests {
 
     #[test]
     fn test_exec_incr_one() {
-        let result = exec(&jit(&vec![Insn::Incr])).expect("mmap failure.");
+        let result = exec(&jit(&vec![Insn::Incr(1)])).expect("mmap failure.");
         assert_eq!(1, result);
     }
 
     #[test]
     fn test_exec_decr_one() {
-        let result = exec(&jit(&vec![Insn::Decr])).expect("mmap failure.");
+        let result = exec(&jit(&vec![Insn::Decr(1)])).expect("mmap failure.");
         assert_eq!(-1, result);
     }
 
     #[test]
     fn test_exec_incr_double_double() {
-        let result =
-            exec(&jit(&vec![Insn::Incr, Insn::Double, Insn::Double])).expect("mmap failure.");
+        let result = exec(&jit(&vec![Insn::Incr(1), Insn::Double(2), Insn::Double(2)]))
+            .expect("mmap failure.");
         assert_eq!(4, result);
     }
 
     #[test]
     fn test_exec_decr_double_double() {
-        let result =
-            exec(&jit(&vec![Insn::Decr, Insn::Double, Insn::Double])).expect("mmap failure.");
+        let result = exec(&jit(&vec![Insn::Decr(1), Insn::Double(2), Insn::Double(2)]))
+            .expect("mmap failure.");
         assert_eq!(-4, result);
     }
 
     #[test]
     fn test_exec_simple() {
         let result = exec(&jit(&vec![
-            Insn::Incr,   // 1
-            Insn::Double, // 2
-            Insn::Double, // 4
-            Insn::Double, // 8
-            Insn::Decr,   // 7
-            Insn::Decr,   // 6
-            Insn::Halve,  // 3
+            Insn::Incr(1),   // 1
+            Insn::Double(2), // 2
+            Insn::Double(2), // 4
+            Insn::Double(2), // 8
+            Insn::Decr(1),   // 7
+            Insn::Decr(1),   // 6
+            Insn::Halve(2),  // 3
         ]))
         .expect("mmap failure.");
 
         assert_eq!(3, result);
     }
+
+    #[test]
+    fn test_exec_large_immediates() {
+        // Exercises the imm32 ADD form and the RCX-loaded IMUL path.
+        let result = exec(&jit(&vec![Insn::Incr(1000), Insn::Double(1000)])).expect("mmap failure.");
+        assert_eq!(1_000_000, result);
+    }
+
+    #[test]
+    fn test_exec_huge_multiplier() {
+        // Exercises the MOV r64, imm64 path for multipliers that don't fit in imm32.
+        let result = exec(&jit(&vec![Insn::Incr(2), Insn::Double(5_000_000_000)]))
+            .expect("mmap failure.");
+        assert_eq!(10_000_000_000, result);
+    }
+
+    #[test]
+    fn test_exec_loop_drains_to_zero() {
+        let result = exec(&jit(&parse("+5[-]"))).expect("mmap failure.");
+        assert_eq!(0, result);
+    }
+
+    #[test]
+    fn test_exec_loop_skipped_when_already_zero() {
+        let result = exec(&jit(&parse("[+100]"))).expect("mmap failure.");
+        assert_eq!(0, result);
+    }
+
+    #[test]
+    fn test_exec_nested_loop() {
+        // An inner loop draining to zero inside an outer loop's body; since both loops share
+        // the one accumulator, the outer loop's own zero-check ends up satisfied by the inner
+        // loop's drain.
+        let result = exec(&jit(&parse("+4[-1[-1]]"))).expect("mmap failure.");
+        assert_eq!(0, result);
+    }
+
+    #[test]
+    fn test_exec_conditional_runs_when_nonzero() {
+        let result = exec(&jit(&parse("+3(+10)"))).expect("mmap failure.");
+        assert_eq!(13, result);
+    }
+
+    #[test]
+    fn test_exec_conditional_skipped_when_zero() {
+        let result = exec(&jit(&parse("(+10)"))).expect("mmap failure.");
+        assert_eq!(0, result);
+    }
+
+    #[test]
+    fn test_exec_f64_reset_returns_zero() {
+        let result = exec_f64(&jit_f64(&Vec::new())).expect("mmap failure.");
+        assert_eq!(0.0, result);
+    }
+
+    #[test]
+    fn test_exec_f64_true_division() {
+        // Unlike the integer path, `/` here is true division rather than truncating.
+        let result = exec_f64(&jit_f64(&parse("+1/4"))).expect("mmap failure.");
+        assert!((result - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_exec_f64_arithmetic() {
+        let result = exec_f64(&jit_f64(&parse("+10*3-5/2"))).expect("mmap failure.");
+        assert!((result - 12.5).abs() < 1e-9);
+    }
 }
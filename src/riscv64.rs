@@ -0,0 +1,243 @@
+use super::Insn;
+
+// Integer registers used below, by their ABI names.
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const A0: u32 = 10; // accumulator, matching the C return-value convention
+const T0: u32 = 5; // scratch
+const T1: u32 = 6; // scratch
+
+// Floating-point registers (a separate register file from the integers above, but numbered the
+// same way), used by the float accumulator mode.
+const FA0: u32 = 10; // float accumulator
+const FT5: u32 = 5; // scratch
+
+// Surely this could be done with transmute() or something similar... (see aarch64.rs)
+fn riscv_insns(words: Vec<u32>) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+// I-type: imm[11:0] | rs1(5) | funct3(3) | rd(5) | opcode(7)
+fn itype(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i32) -> u32 {
+    opcode | (rd << 7) | (funct3 << 12) | (rs1 << 15) | (((imm as u32) & 0xfff) << 20)
+}
+
+// U-type: imm[31:12] | rd(5) | opcode(7)
+fn utype(opcode: u32, rd: u32, imm20: i32) -> u32 {
+    opcode | (rd << 7) | (((imm20 as u32) & 0xfffff) << 12)
+}
+
+// R-type: funct7(7) | rs2(5) | rs1(5) | funct3(3) | rd(5) | opcode(7). Also covers the OP-FP
+// (0x53) encodings used by the float instructions below, where `funct3` holds the rounding mode.
+fn rtype(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    opcode | (rd << 7) | (funct3 << 12) | (rs1 << 15) | (rs2 << 20) | (funct7 << 25)
+}
+
+// SB-type (branches): imm[12|10:5] | rs2(5) | rs1(5) | funct3(3) | imm[4:1|11] | opcode(7).
+// Branch offsets are always even, so bit 0 of `imm` is implicit and not encoded.
+fn sbtype(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    let imm11 = (imm >> 11) & 0x1;
+    let imm4_1 = (imm >> 1) & 0xf;
+    let imm10_5 = (imm >> 5) & 0x3f;
+    let imm12 = (imm >> 12) & 0x1;
+    opcode | (imm11 << 7) | (imm4_1 << 8) | (funct3 << 12) | (rs1 << 15) | (rs2 << 20) | (imm10_5 << 25) | (imm12 << 31)
+}
+
+// J-type (JAL): imm[20|10:1|11|19:12] | rd(5) | opcode(7). Like SB-type, bit 0 is implicit.
+fn jtype(opcode: u32, rd: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    let imm19_12 = (imm >> 12) & 0xff;
+    let imm11 = (imm >> 11) & 0x1;
+    let imm10_1 = (imm >> 1) & 0x3ff;
+    let imm20 = (imm >> 20) & 0x1;
+    opcode | (rd << 7) | (imm19_12 << 12) | (imm11 << 20) | (imm10_1 << 21) | (imm20 << 31)
+}
+
+// Materializes a 32-bit value into `rd` with LUI + ADDIW, or a lone ADDI when the upper 20 bits
+// are zero. `lo12` is sign-extended, so `hi20` is rounded up to compensate when it's negative;
+// ADDIW wraps at 32 bits and sign-extends, so values just below i32::MAX (where `hi20` comes out
+// as 0x80000 and LUI sign-extends it to a negative) still come out right.
+fn li32(rd: u32, val: i64) -> Vec<u32> {
+    let lo12 = (((val & 0xfff) as i32) << 20) >> 20; // sign-extend the low 12 bits
+    let hi20 = ((val - lo12 as i64) >> 12) as i32; // exact: val == (hi20 << 12) + lo12
+
+    let mut words = Vec::new();
+    if hi20 != 0 {
+        words.push(utype(0x37, rd, hi20)); // LUI rd, hi20
+        if lo12 != 0 {
+            words.push(itype(0x1B, rd, 0b000, rd, lo12)); // ADDIW rd, rd, lo12
+        }
+    } else {
+        words.push(itype(0x13, rd, 0b000, ZERO, lo12)); // ADDI rd, x0, lo12 (li rd, lo12)
+    }
+    words
+}
+
+// Materializes an arbitrary 64-bit immediate into `rd`, using `scratch` as a second register
+// when the low and high halves both need their own `li32` sequence. Splits `value` into a
+// sign-extended low 32 bits and a high 32 bits chosen so the two recombine exactly under 64-bit
+// two's complement addition, the same compensation trick `li32` uses for its sign-extended lo12.
+fn li64(rd: u32, value: i64, scratch: u32) -> Vec<u32> {
+    if let Ok(v32) = i32::try_from(value) {
+        return li32(rd, v32 as i64);
+    }
+
+    let lo32 = value as i32 as i64; // sign-extended low 32 bits
+    let hi32 = (value - lo32) >> 32; // exact: value == (hi32 << 32) + lo32
+
+    let mut words = li32(rd, hi32);
+    words.push(itype(0x13, rd, 0b001, rd, 32)); // SLLI rd, rd, 32
+    words.extend(li32(scratch, lo32));
+    words.push(rtype(0x33, rd, 0b000, rd, scratch, 0b0000000)); // ADD rd, rd, scratch
+    words
+}
+
+fn reset_accum() -> Vec<u8> {
+    // ADDI a0, x0, 0 (li a0, 0)
+    riscv_insns(vec![itype(0x13, A0, 0b000, ZERO, 0)])
+}
+
+fn func_return() -> Vec<u8> {
+    // JALR x0, x1, 0 (ret)
+    riscv_insns(vec![itype(0x67, ZERO, 0b000, RA, 0)])
+}
+
+fn incr(imm: i64) -> Vec<u8> {
+    if (-2048..=2047).contains(&imm) {
+        return riscv_insns(vec![itype(0x13, A0, 0b000, A0, imm as i32)]); // ADDI a0, a0, imm
+    }
+    let mut words = li64(T0, imm, T1);
+    words.push(rtype(0x33, A0, 0b000, A0, T0, 0b0000000)); // ADD a0, a0, t0
+    riscv_insns(words)
+}
+
+fn decr(imm: i64) -> Vec<u8> {
+    if (-2047..=2048).contains(&imm) {
+        return riscv_insns(vec![itype(0x13, A0, 0b000, A0, -imm as i32)]); // ADDI a0, a0, -imm
+    }
+    let mut words = li64(T0, imm, T1);
+    words.push(rtype(0x33, A0, 0b000, A0, T0, 0b0100000)); // SUB a0, a0, t0
+    riscv_insns(words)
+}
+
+fn double(imm: i64) -> Vec<u8> {
+    let mut words = li64(T0, imm, T1);
+    words.push(rtype(0x33, A0, 0b000, A0, T0, 0b0000001)); // MUL a0, a0, t0
+    riscv_insns(words)
+}
+
+fn halve(imm: i64) -> Vec<u8> {
+    let mut words = li64(T0, imm, T1);
+    words.push(rtype(0x33, A0, 0b100, A0, T0, 0b0000001)); // DIV a0, a0, t0
+    riscv_insns(words)
+}
+
+// BEQ a0, x0, #0: the immediate is left zeroed; returns the bytes and the byte offset of the
+// whole instruction word, which `patch_branch` rewrites once the branch target is known.
+pub fn branch_if_zero() -> (Vec<u8>, usize) {
+    (riscv_insns(vec![sbtype(0x63, 0b000, A0, ZERO, 0)]), 0)
+}
+
+// JAL x0, #0: like `branch_if_zero`, the offset returned is the start of the word.
+pub fn jump() -> (Vec<u8>, usize) {
+    (riscv_insns(vec![jtype(0x6F, ZERO, 0)]), 0)
+}
+
+// Patches the placeholder word at `site` so it branches to `target`. Both SB-type and J-type
+// immediates encode `target - site` with bit 0 implicit, since branch/jump targets are always
+// 2-byte aligned; which form to re-encode is read back from the placeholder's opcode bits (the
+// register fields for each form are fixed at encode time, so there's nothing else to preserve).
+pub fn patch_branch(code: &mut [u8], site: usize, target: usize) {
+    let disp = target as i64 - site as i64;
+    assert_eq!(disp % 2, 0, "riscv64 branch target must be 2-byte aligned");
+    // BEQ only has +/-4KiB reach (JAL +/-1MiB); widening an out-of-range branch into a chain
+    // that hops through a trampoline isn't implemented, not disallowed by spec.
+    let imm = i32::try_from(disp).expect("branch offset out of range (widening not implemented)");
+
+    let word = u32::from_le_bytes(code[site..site + 4].try_into().unwrap());
+    let patched = if (word & 0x7f) == 0x63 {
+        assert!(
+            (-(1 << 12)..(1 << 12)).contains(&imm),
+            "branch offset doesn't fit BEQ's 13-bit immediate (widening not implemented)"
+        );
+        sbtype(0x63, 0b000, A0, ZERO, imm) // BEQ a0, x0, imm
+    } else {
+        assert!(
+            (-(1 << 20)..(1 << 20)).contains(&imm),
+            "branch offset doesn't fit JAL's 21-bit immediate (widening not implemented)"
+        );
+        jtype(0x6F, ZERO, imm) // JAL x0, imm
+    };
+    code[site..site + 4].copy_from_slice(&patched.to_le_bytes());
+}
+
+pub fn native_insns(insn: &Insn) -> Vec<u8> {
+    match insn {
+        Insn::Reset => reset_accum(),
+        Insn::Return => func_return(),
+        Insn::Incr(imm) => incr(*imm),
+        Insn::Decr(imm) => decr(*imm),
+        Insn::Double(imm) => double(*imm),
+        Insn::Halve(imm) => halve(*imm),
+        Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_) => {
+            unreachable!("control-flow instructions are lowered directly by jit(), not native_insns")
+        }
+    }
+}
+
+// Float accumulator mode: the accumulator lives in FA0 as an `f64`, using the D extension. T0/T1
+// and FT5 are scratch, same roles they play in the integer backend above.
+
+fn reset_accum_f64() -> Vec<u8> {
+    // FMV.D.X fa0, x0: moves the all-zero bit pattern into fa0, giving exactly 0.0.
+    riscv_insns(vec![rtype(0x53, FA0, 0b000, ZERO, 0b00000, 0b1111001)])
+}
+
+fn func_return_f64() -> Vec<u8> {
+    func_return()
+}
+
+fn load_imm_into_ft5(imm: i64) -> Vec<u32> {
+    let mut words = li64(T0, imm, T1);
+    words.push(rtype(0x53, FT5, 0b000, T0, 0b00010, 0b1101001)); // FCVT.D.L ft5, t0
+    words
+}
+
+fn incr_f64(imm: i64) -> Vec<u8> {
+    let mut words = load_imm_into_ft5(imm);
+    words.push(rtype(0x53, FA0, 0b000, FA0, FT5, 0b0000001)); // FADD.D fa0, fa0, ft5
+    riscv_insns(words)
+}
+
+fn decr_f64(imm: i64) -> Vec<u8> {
+    let mut words = load_imm_into_ft5(imm);
+    words.push(rtype(0x53, FA0, 0b000, FA0, FT5, 0b0000101)); // FSUB.D fa0, fa0, ft5
+    riscv_insns(words)
+}
+
+fn double_f64(imm: i64) -> Vec<u8> {
+    let mut words = load_imm_into_ft5(imm);
+    words.push(rtype(0x53, FA0, 0b000, FA0, FT5, 0b0001001)); // FMUL.D fa0, fa0, ft5
+    riscv_insns(words)
+}
+
+fn halve_f64(imm: i64) -> Vec<u8> {
+    let mut words = load_imm_into_ft5(imm);
+    words.push(rtype(0x53, FA0, 0b000, FA0, FT5, 0b0001101)); // FDIV.D fa0, fa0, ft5
+    riscv_insns(words)
+}
+
+pub fn native_insns_f64(insn: &Insn) -> Vec<u8> {
+    match insn {
+        Insn::Reset => reset_accum_f64(),
+        Insn::Return => func_return_f64(),
+        Insn::Incr(imm) => incr_f64(*imm),
+        Insn::Decr(imm) => decr_f64(*imm),
+        Insn::Double(imm) => double_f64(*imm),
+        Insn::Halve(imm) => halve_f64(*imm),
+        Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_) => {
+            unreachable!("control-flow instructions are not supported in float mode")
+        }
+    }
+}
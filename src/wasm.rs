@@ -0,0 +1,281 @@
+use super::Insn;
+
+// Lowers an `Insn` stream to a standalone WebAssembly module, for hosts that can't run the
+// native `x86_64`/`aarch64` JIT backends (or just to inspect what the calculator compiles to).
+// The accumulator becomes a single `i64` local; the emitted function takes no parameters and
+// returns that local's final value.
+
+const WASM_MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6D]; // "\0asm"
+const WASM_VERSION: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+const VALTYPE_I64: u8 = 0x7E;
+const FUNCTYPE: u8 = 0x60;
+const EXPORT_KIND_FUNC: u8 = 0x00;
+
+const OP_LOCAL_GET: u8 = 0x20;
+const OP_LOCAL_SET: u8 = 0x21;
+const OP_I64_CONST: u8 = 0x42;
+const OP_I64_ADD: u8 = 0x7C;
+const OP_I64_SUB: u8 = 0x7D;
+const OP_I64_MUL: u8 = 0x7E;
+const OP_I64_DIV_S: u8 = 0x7F;
+const OP_I64_EQZ: u8 = 0x50;
+const OP_I64_NE: u8 = 0x52;
+const OP_BLOCK: u8 = 0x02;
+const OP_LOOP: u8 = 0x03;
+const OP_IF: u8 = 0x04;
+const OP_END: u8 = 0x0B;
+const OP_BR: u8 = 0x0C;
+const OP_BR_IF: u8 = 0x0D;
+const BLOCKTYPE_VOID: u8 = 0x40;
+
+const ACCUM_LOCAL: u64 = 0;
+
+fn leb128_u(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn leb128_s(mut value: i64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let sign_bit_set = byte & 0x40 != 0;
+        if (value == 0 && !sign_bit_set) || (value == -1 && sign_bit_set) {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn emit_arith(body: &mut Vec<u8>, imm: i64, op: u8) {
+    body.push(OP_LOCAL_GET);
+    leb128_u(ACCUM_LOCAL, body);
+    body.push(OP_I64_CONST);
+    leb128_s(imm, body);
+    body.push(op);
+    body.push(OP_LOCAL_SET);
+    leb128_u(ACCUM_LOCAL, body);
+}
+
+fn encode_insn(insn: &Insn, body: &mut Vec<u8>) {
+    match insn {
+        Insn::Reset => {
+            body.push(OP_I64_CONST);
+            leb128_s(0, body);
+            body.push(OP_LOCAL_SET);
+            leb128_u(ACCUM_LOCAL, body);
+        }
+        Insn::Incr(imm) => emit_arith(body, *imm, OP_I64_ADD),
+        Insn::Decr(imm) => emit_arith(body, *imm, OP_I64_SUB),
+        Insn::Double(imm) => emit_arith(body, *imm, OP_I64_MUL),
+        Insn::Halve(imm) => emit_arith(body, *imm, OP_I64_DIV_S),
+        Insn::Return => {
+            // Leaves the accumulator on the stack; the function's implicit `end` returns it.
+            body.push(OP_LOCAL_GET);
+            leb128_u(ACCUM_LOCAL, body);
+        }
+        Insn::LoopStart(_) => {
+            // The classic `block`/`loop`/`br_if` idiom for a structured while-loop: `loop`
+            // is label 0 (continue), the enclosing `block` is label 1 (break).
+            body.push(OP_BLOCK);
+            body.push(BLOCKTYPE_VOID);
+            body.push(OP_LOOP);
+            body.push(BLOCKTYPE_VOID);
+            body.push(OP_LOCAL_GET);
+            leb128_u(ACCUM_LOCAL, body);
+            body.push(OP_I64_EQZ);
+            body.push(OP_BR_IF);
+            leb128_u(1, body);
+        }
+        Insn::LoopEnd(_) => {
+            body.push(OP_BR);
+            leb128_u(0, body); // back to the top of the loop, re-running its zero-check
+            body.push(OP_END); // end loop
+            body.push(OP_END); // end block
+        }
+        Insn::IfZeroSkip(_) => {
+            body.push(OP_LOCAL_GET);
+            leb128_u(ACCUM_LOCAL, body);
+            body.push(OP_I64_CONST);
+            leb128_s(0, body);
+            body.push(OP_I64_NE);
+            body.push(OP_IF);
+            body.push(BLOCKTYPE_VOID);
+        }
+        Insn::IfZeroEnd(_) => {
+            body.push(OP_END);
+        }
+    }
+}
+
+fn section(module: &mut Vec<u8>, id: u8, body: Vec<u8>) {
+    module.push(id);
+    leb128_u(body.len() as u64, module);
+    module.extend(body);
+}
+
+/// Lowers a program's `Insn` stream into a complete `.wasm` module exporting a single
+/// `calc: () -> i64` function.
+pub fn emit_wasm(insn_seq: &[Insn]) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_insn(&Insn::Reset, &mut body);
+    for insn in insn_seq {
+        encode_insn(insn, &mut body);
+    }
+    encode_insn(&Insn::Return, &mut body);
+    body.push(OP_END);
+
+    let mut func_body = Vec::new();
+    leb128_u(1, &mut func_body); // 1 group of locals
+    leb128_u(1, &mut func_body); // 1 local in that group
+    func_body.push(VALTYPE_I64);
+    func_body.extend(body);
+
+    let mut type_section = Vec::new();
+    leb128_u(1, &mut type_section); // 1 type
+    type_section.push(FUNCTYPE);
+    leb128_u(0, &mut type_section); // 0 params
+    leb128_u(1, &mut type_section); // 1 result
+    type_section.push(VALTYPE_I64);
+
+    let mut function_section = Vec::new();
+    leb128_u(1, &mut function_section); // 1 function
+    leb128_u(0, &mut function_section); // using type index 0
+
+    let mut export_section = Vec::new();
+    leb128_u(1, &mut export_section); // 1 export
+    let name = b"calc";
+    leb128_u(name.len() as u64, &mut export_section);
+    export_section.extend(name);
+    export_section.push(EXPORT_KIND_FUNC);
+    leb128_u(0, &mut export_section); // function index 0
+
+    let mut code_section = Vec::new();
+    leb128_u(1, &mut code_section); // 1 function body
+    leb128_u(func_body.len() as u64, &mut code_section);
+    code_section.extend(func_body);
+
+    let mut module = Vec::new();
+    module.extend(WASM_MAGIC);
+    module.extend(WASM_VERSION);
+    section(&mut module, SECTION_TYPE, type_section);
+    section(&mut module, SECTION_FUNCTION, function_section);
+    section(&mut module, SECTION_EXPORT, export_section);
+    section(&mut module, SECTION_CODE, code_section);
+
+    module
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        emit_wasm, encode_insn, leb128_s, leb128_u, Insn, OP_I64_ADD, OP_I64_DIV_S, OP_I64_MUL,
+        OP_I64_SUB, SECTION_CODE, SECTION_EXPORT, SECTION_FUNCTION, SECTION_TYPE, WASM_MAGIC,
+        WASM_VERSION,
+    };
+
+    #[test]
+    fn test_leb128_u_small() {
+        let mut out = Vec::new();
+        leb128_u(5, &mut out);
+        assert_eq!(vec![0x05], out);
+    }
+
+    #[test]
+    fn test_leb128_u_multibyte() {
+        // 300 = 0b1_0010_1100 -> low 7 bits 0x2C with continuation, then 0x02
+        let mut out = Vec::new();
+        leb128_u(300, &mut out);
+        assert_eq!(vec![0xAC, 0x02], out);
+    }
+
+    #[test]
+    fn test_leb128_s_positive() {
+        let mut out = Vec::new();
+        leb128_s(5, &mut out);
+        assert_eq!(vec![0x05], out);
+    }
+
+    #[test]
+    fn test_leb128_s_negative() {
+        // -5 sign-extended into 7-bit groups: 0x7B with no continuation needed
+        let mut out = Vec::new();
+        leb128_s(-5, &mut out);
+        assert_eq!(vec![0x7B], out);
+    }
+
+    #[test]
+    fn test_encode_halve_uses_signed_div() {
+        // Regression check: `i64.div_s` (0x7F) must be emitted, not `i64.div_u` (0x80).
+        let mut body = Vec::new();
+        encode_insn(&Insn::Halve(2), &mut body);
+        assert!(body.contains(&OP_I64_DIV_S));
+        assert_eq!(OP_I64_DIV_S, 0x7F);
+    }
+
+    #[test]
+    fn test_encode_arith_opcodes() {
+        let mut incr = Vec::new();
+        encode_insn(&Insn::Incr(1), &mut incr);
+        assert!(incr.contains(&OP_I64_ADD));
+
+        let mut decr = Vec::new();
+        encode_insn(&Insn::Decr(1), &mut decr);
+        assert!(decr.contains(&OP_I64_SUB));
+
+        let mut double = Vec::new();
+        encode_insn(&Insn::Double(2), &mut double);
+        assert!(double.contains(&OP_I64_MUL));
+    }
+
+    #[test]
+    fn test_emit_wasm_header() {
+        let module = emit_wasm(&[]);
+        assert_eq!(WASM_MAGIC, module[0..4]);
+        assert_eq!(WASM_VERSION, module[4..8]);
+    }
+
+    #[test]
+    fn test_emit_wasm_section_order() {
+        // Sections must appear in ascending id order: type(1), function(3), export(7), code(10).
+        let module = emit_wasm(&[]);
+        let section_ids = vec![SECTION_TYPE, SECTION_FUNCTION, SECTION_EXPORT, SECTION_CODE];
+
+        let mut found_ids = Vec::new();
+        let mut i = 8; // skip magic + version
+        while i < module.len() {
+            found_ids.push(module[i]);
+            let mut j = i + 1;
+            let mut size: u64 = 0;
+            let mut shift = 0;
+            loop {
+                let byte = module[j];
+                size |= ((byte & 0x7f) as u64) << shift;
+                j += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                shift += 7;
+            }
+            i = j + size as usize;
+        }
+
+        assert_eq!(section_ids, found_ids);
+    }
+}
@@ -1,183 +1,153 @@
+use super::asm::{Assembler, Reg64, Xmm};
 use super::Insn;
 
-// All instructions assume the value to operate on is in EAX and the result should be stored in
-// EAX as well. This aligns with the x86 conventions.
-
-// Format of the REX prefix byte, from https://pyokagan.name/blog/2019-09-20-x86encoding/
-// 0100	4 bits	Fixed bit pattern
-// W	1 bit	When 1, a 64-bit operand size is used. Otherwise, when 0, the default operand size is used (which is 32-bit for most but not all instructions)
-// R	1 bit	This 1-bit value is an extension to the MODRM.reg field.
-// X	1 bit	This 1-bit value is an extension to the SIB.index field.
-// B	1 bit	This 1-bit value is an extension to the MODRM.rm field or the SIB.base field.
-
-#[allow(dead_code)]
-enum Rex {
-    W,
-    R,
-    X,
-    B,
-}
-
-fn rex(opts: &[Rex]) -> u8 {
-    let mut rex: u8 = 0b01000000;
-    for opt in opts {
-        let shift = match opt {
-            Rex::W => 3,
-            Rex::R => 2,
-            Rex::X => 1,
-            Rex::B => 0,
-        };
-        rex = rex | 0x01 << shift;
-    }
-    rex
-}
-
-enum ModRM {
-    Mod(u8),
-    Reg(u8),
-    RM(u8),
-}
-
-fn modrm(parts: &[ModRM]) -> u8 {
-    // mod, reg, r/m
-    // mm, rrr, bbb
-    let mut modrm: u8 = 0x0;
-
-    for part in parts {
-        match part {
-            ModRM::Mod(m) => {
-                modrm = modrm | ((m & 0b00000011) << 6);
-            }
-            ModRM::Reg(r) => {
-                modrm = modrm | ((r & 0b00000111) << 3);
-            }
-            ModRM::RM(b) => {
-                modrm = modrm | (b & 0b00000111);
-            }
-        }
-    }
-    modrm
-}
-
-// From https://cs.wellesley.edu/~cs342/fall12/papers/isa.pdf
-// A slash followed by a digit, such as /2, indicates that one of the operands to the instruction
-// is a memory address or register (denoted mem or r/m, with an optional size). This is to be
-// encoded as an effective address, with a ModR/M byte, an optional SIB byte, and an optional
-// displacement, and the spare (register) field of the ModR/M byte should be the digit given
-// (which will be from 0 to 7, so it fits in three bits). The encoding of effective addresses
-// is given in section A.2.3.
-//
-// A.2.3
-// An effective address is encoded in up to three parts: a ModR/M byte, an optional SIB byte,
-// and an optional byte, word or doubleword displacement field.
-//
-// The ModR/M byte consists of three fields: the mod field, ranging from 0 to 3, in the upper
-// two bits of the byte, the r/m field, ranging from 0 to 7, in the lower three bits, and the
-// spare (register) field in the middle (bit 3 to bit 5). The spare field is not relevant to
-// the effective address being encoded, and either contains an extension to the instruction
-// opcode or the register value of another operand.
+// All instructions assume the value to operate on is in RAX and the result should be stored in
+// RAX as well. This aligns with the x86 conventions.
 
 fn reset_accum() -> Vec<u8> {
-    // XOR r/m64, r64
-    // REX.W + 31 /r
-    vec![
-        rex(&[Rex::W]),
-        0x31,
-        modrm(&[
-            ModRM::Mod(0x3),
-            ModRM::Reg(0x00), // rax
-        ]),
-    ]
+    let mut asm = Assembler::new();
+    asm.xor(Reg64::Rax.into(), Reg64::Rax);
+    asm.finish()
 }
 
 fn func_return() -> Vec<u8> {
-    // RET
-    vec![0xC3]
-}
-
-fn incr() -> Vec<u8> {
-    vec![
-        // ADD r/m64, imm8
-        // REX.W + 83 /0 ib
-        rex(&[Rex::W]),
-        0x83,
-        modrm(&[ModRM::Mod(0x3)]),
-        0x01,
-    ]
-}
-
-fn decr() -> Vec<u8> {
-    // SUB r/m64, imm32
-    // REX.W + 81 /5 id
-    vec![
-        rex(&[Rex::W]),
-        0x81,
-        modrm(&[ModRM::Mod(0x3), ModRM::Reg(0x05), ModRM::RM(0x00)]),
-        0x01,
-        0x00,
-        0x00,
-        0x00,
-    ]
-}
-
-fn double() -> Vec<u8> {
-    vec![
-        // MOV rcx, DWORD 0x02
-        // REX.W + C7 /0 io
-        rex(&[Rex::W]),
-        0xC7,
-        modrm(&[ModRM::Mod(0x3), ModRM::RM(0x01)]),
-        0x02,
-        0x00,
-        0x00,
-        0x00,
-        //
-        // IMUL r/m64 -> REX.W + F7 /5
-        // RDX:RAX ← RAX ∗ r/m64.
-        // IMUL rcx
-        rex(&[Rex::W]),
-        0xF7,
-        modrm(&[
-            ModRM::Mod(0x3),  // register addressing
-            ModRM::Reg(0x05), // literal,
-            ModRM::RM(0x01),  // rcx
-        ]),
-    ]
-}
-
-fn halve() -> Vec<u8> {
-    // Signed divide EDX:EAX by r/m32, with result stored in EAX ← Quotient, EDX ← Remainder.
-    // IDIV r/m32 -> 0xF7 /7 -> ???
-    // op1 -> ModRM:r/m (r)
-    vec![
-        // MOV rcx, DWORD 0x02
-        // REX.W + C7 /0 io
-        rex(&[Rex::W]),
-        0xC7,
-        modrm(&[ModRM::Mod(0x3), ModRM::RM(0x01)]),
-        0x02,
-        0x00,
-        0x00,
-        0x00,
-        //
-        // IDIV REX.W + F7 /7
-        rex(&[Rex::W]),
-        0xF7,
-        modrm(&[
-            ModRM::Mod(0x3),  // register addressing
-            ModRM::Reg(0x07), // literal
-            ModRM::RM(0x01),  // rcx
-        ]),
-    ]
+    let mut asm = Assembler::new();
+    asm.ret();
+    asm.finish()
+}
+
+fn incr(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    asm.add(Reg64::Rax.into(), imm);
+    asm.finish()
+}
+
+fn decr(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    asm.sub(Reg64::Rax.into(), imm);
+    asm.finish()
+}
+
+fn double(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    asm.mov_imm(Reg64::Rcx, imm);
+    asm.imul(Reg64::Rcx.into());
+    asm.finish()
+}
+
+fn halve(imm: i64) -> Vec<u8> {
+    // Signed divide RDX:RAX by r/m64, with result stored in RAX <- Quotient, RDX <- Remainder.
+    // CQO sign-extends RAX into RDX first so a negative accumulator divides correctly instead
+    // of dividing by whatever garbage RDX previously held.
+    let mut asm = Assembler::new();
+    asm.mov_imm(Reg64::Rcx, imm);
+    asm.cqo();
+    asm.idiv(Reg64::Rcx.into());
+    asm.finish()
+}
+
+// Tests RAX against zero and leaves an unresolved JZ rel32, for branches guarding a loop or
+// conditional body. Returns the emitted bytes and the byte offset within them of the 4-byte
+// displacement field, which the caller patches in with `patch_branch` once the target is known.
+pub fn branch_if_zero() -> (Vec<u8>, usize) {
+    let mut asm = Assembler::new();
+    asm.test_self(Reg64::Rax);
+    let site = asm.jz();
+    (asm.finish(), site)
+}
+
+// An unresolved JMP rel32, for the backward branch at the bottom of a loop.
+pub fn jump() -> (Vec<u8>, usize) {
+    let mut asm = Assembler::new();
+    let site = asm.jmp();
+    (asm.finish(), site)
+}
+
+// Patches the rel32 displacement at `site` (the offset returned by `branch_if_zero`/`jump`) so
+// the branch lands on `target`: disp = target - (site + 4), since x86 relative branches are
+// measured from the address of the byte following the displacement field.
+pub fn patch_branch(code: &mut [u8], site: usize, target: usize) {
+    let disp = target as i64 - (site as i64 + 4);
+    // rel32 covers +/-2GiB, so this is unreachable for any realistic program; branch widening
+    // (splitting into a chain of short jumps) isn't implemented, not disallowed by spec.
+    let disp32 =
+        i32::try_from(disp).expect("branch target out of range for rel32 (widening not implemented)");
+    code[site..site + 4].copy_from_slice(&disp32.to_le_bytes());
 }
 
 pub fn native_insns(insn: &Insn) -> Vec<u8> {
     match insn {
         Insn::Reset => reset_accum(),
         Insn::Return => func_return(),
-        Insn::Incr => incr(),
-        Insn::Decr => decr(),
-        Insn::Double => double(),
-        Insn::Halve => halve(),
+        Insn::Incr(imm) => incr(*imm),
+        Insn::Decr(imm) => decr(*imm),
+        Insn::Double(imm) => double(*imm),
+        Insn::Halve(imm) => halve(*imm),
+        Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_) => {
+            unreachable!("control-flow instructions are lowered directly by jit(), not native_insns")
+        }
+    }
+}
+
+// Float accumulator mode: same six `Insn`s, but the accumulator lives in XMM0 as an `f64` and
+// each immediate is converted from its integer literal into a double before the SSE2 op runs.
+// RCX and XMM1 are scratch, same roles as RCX plays in the integer backend above.
+
+fn reset_accum_f64() -> Vec<u8> {
+    let mut asm = Assembler::new();
+    asm.xorpd(Xmm::Xmm0, Xmm::Xmm0);
+    asm.finish()
+}
+
+fn func_return_f64() -> Vec<u8> {
+    let mut asm = Assembler::new();
+    asm.ret();
+    asm.finish()
+}
+
+fn load_imm_as_f64(asm: &mut Assembler, imm: i64) {
+    asm.mov_imm(Reg64::Rcx, imm);
+    asm.cvtsi2sd(Xmm::Xmm1, Reg64::Rcx);
+}
+
+fn incr_f64(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    load_imm_as_f64(&mut asm, imm);
+    asm.addsd(Xmm::Xmm0, Xmm::Xmm1);
+    asm.finish()
+}
+
+fn decr_f64(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    load_imm_as_f64(&mut asm, imm);
+    asm.subsd(Xmm::Xmm0, Xmm::Xmm1);
+    asm.finish()
+}
+
+fn double_f64(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    load_imm_as_f64(&mut asm, imm);
+    asm.mulsd(Xmm::Xmm0, Xmm::Xmm1);
+    asm.finish()
+}
+
+fn halve_f64(imm: i64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    load_imm_as_f64(&mut asm, imm);
+    asm.divsd(Xmm::Xmm0, Xmm::Xmm1);
+    asm.finish()
+}
+
+pub fn native_insns_f64(insn: &Insn) -> Vec<u8> {
+    match insn {
+        Insn::Reset => reset_accum_f64(),
+        Insn::Return => func_return_f64(),
+        Insn::Incr(imm) => incr_f64(*imm),
+        Insn::Decr(imm) => decr_f64(*imm),
+        Insn::Double(imm) => double_f64(*imm),
+        Insn::Halve(imm) => halve_f64(*imm),
+        Insn::LoopStart(_) | Insn::LoopEnd(_) | Insn::IfZeroSkip(_) | Insn::IfZeroEnd(_) => {
+            unreachable!("control-flow instructions are not supported in float mode")
+        }
     }
 }